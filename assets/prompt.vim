@@ -14,24 +14,32 @@ syntax include @Yaml syntax/yaml.vim
 unlet b:current_syntax
 syntax region yamlCodeContained start=+^\s\+direction\ze: + end=+^\({\)\@=+ contained contains=@Yaml
 
-syntax region yamlCode start=+^\s\+\%(description\|direction\|eager\|history\)\ze: + end=+^\({\)\@=+ contains=@Yaml,yamlCodeContained,bashCodeContained
+syntax region yamlCode start=+^\s\+\%(description\|direction\|eager\|history\|max_words\|stop\|tokens_balance\)\ze: + end=+^\({\)\@=+ contains=@Yaml,yamlCodeContained,bashCodeContained
 syntax region bashCode start='`' end='`' keepend contains=@Bash,bashCodeContained,yamlCodeContained
 
 syntax match promptName '^[A-Z|a-z|0-9|_]\+$' nextgroup=yamlCode
 syntax match promptCall '[A-Z|a-z|0-9|_]\+,'
 syntax keyword promptMatch match nextgroup=promptMatchVariable skipwhite
 syntax match promptMatchVariable '$[A-Z]\+'
+syntax keyword promptPrint print nextgroup=promptString skipwhite
+syntax keyword promptIf if
+syntax region promptString start=+"+ skip=+\\"+ end=+"+ contained contains=promptMatchVariable
 
 syntax match promptRegex '([^)]*)'
 syntax match promptFatArrow '=>'
 syntax match promptSkinnyArrow '->'
+syntax match promptStderr '`\@<=\s*2>'
 syntax match promptComment '#.*'
 
 hi def link promptName Type
 hi def link promptCall Type
 hi def link promptMatch Statement
 hi def link promptMatchVariable PreProc
+hi def link promptPrint Statement
+hi def link promptIf Conditional
+hi def link promptString String
 hi def link promptFatArrow Statement
 hi def link promptSkinnyArrow Statement
+hi def link promptStderr Statement
 hi def link promptRegex Comment
 hi def link promptComment Comment
@@ -1,15 +1,9 @@
+use clap::Parser;
 use prompt::{prompt,PromptArgs};
-use std::path::PathBuf;
 
 #[tokio::main]
 async fn main() {
-    let path = PathBuf::from("./examples/animal_house.pr");
-
-    let args = PromptArgs {
-        path,
-        quiet: false,
-        watch: None
-    };
+    let args = PromptArgs::parse_from(["prompt", "./examples/animal_house/animal_house.pr"]);
 
     prompt(args).await;
 }
@@ -3,7 +3,29 @@ use regex::Regex;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Program {
-    pub prompts: Vec<Prompt>
+    pub prompts: Vec<Prompt>,
+    /// Comments written after the last prompt
+    pub comment: Option<Comment>
+}
+
+impl Program {
+    /// Adds the prompts of another program to this one. The first program merged keeps its main
+    /// prompt, prompts from later programs are never main. Fails with the name of the first prompt
+    /// that's already defined.
+    pub fn merge(&mut self, other: Program) -> Result<(), String> {
+        let has_main = self.prompts.iter().any(|prompt| prompt.is_main);
+
+        for mut prompt in other.prompts {
+            if self.prompts.iter().any(|p| p.name == prompt.name) {
+                return Err(prompt.name);
+            }
+
+            prompt.is_main = prompt.is_main && !has_main;
+            self.prompts.push(prompt);
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -11,6 +33,10 @@ pub struct Prompt {
     pub is_main: bool,
     pub name: String,
     pub options: PromptOptions,
+    /// The YAML options as written in the file, without their indentation
+    pub header: String,
+    /// Comments written right above the prompt
+    pub comment: Option<Comment>,
     pub statements: Vec<Statement>,
 }
 
@@ -20,21 +46,35 @@ pub struct PromptOptions {
     pub direction: Option<String>,
     pub eager: Option<bool>,
     pub history: Option<bool>,
+    pub max_words: Option<usize>,
+    /// Sent to the API, which stops generating before any of them. ai-cli splits each one on
+    /// commas and allows at most 4, which the parser checks.
+    pub stop: Option<Vec<String>>,
+    pub tokens_balance: Option<f32>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Statement {
     MatchStatement(MatchStatement),
     PipeStatement(PipeStatement),
-    Command(Command)
+    Command(Command),
+    Print(Print),
+    IfStatement(IfStatement),
+    Comment(Comment)
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct MatchStatement {
-    pub variable: Variable,
+    pub subject: PipeSubject,
     pub cases: Vec<MatchCase>,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct IfStatement {
+    pub condition: Command,
+    pub statements: Vec<Statement>,
+}
+
 #[derive(Clone, Debug)]
 pub struct MatchCase {
     pub regex: Regex,
@@ -63,6 +103,8 @@ pub struct PipeStatement {
 #[derive(Clone, Debug, PartialEq)]
 pub enum PipeSubject {
     Command(Command),
+    /// A command followed by `2>`, whose stderr is used instead of its stdout
+    Stderr(Command),
     Variable(Variable)
 }
 
@@ -76,3 +118,13 @@ pub struct Command(pub String);
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Variable(pub String);
+
+/// Text to print, still holding its `\\` and `\$` escapes, which are undone along with the
+/// variables when it's printed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Print(pub String);
+
+/// One or more comment lines, each starting with `#`. A blank line between or right after the
+/// comments is kept as an empty line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment(pub String);
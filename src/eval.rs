@@ -1,13 +1,25 @@
-use ai::{Config,ChatCommand,ChatRole,CompletionOptions};
+use ai::{Config,ChatCommand,ChatError,ChatRole,CompletionOptions};
 use reqwest::Client;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead,BufReader};
 use std::path::PathBuf;
-use std::process;
-use tokio::task::JoinError;
-use regex::{Captures,CaptureNames};
+use tokio::task::{JoinError,JoinHandle};
+use regex::{Captures,CaptureNames,Regex};
 use super::ast::*;
+use super::output::write_stdout;
 use futures::future::join_all;
 use futures::Future;
 use std::pin::Pin;
+use std::sync::{Arc,LazyLock,Mutex};
+use std::task::{Context,Poll};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Held while asking to run a command, so prompts running in parallel ask one at a time.
+static CONFIRM_LOCK: LazyLock<tokio::sync::Mutex<()>> =
+    LazyLock::new(|| tokio::sync::Mutex::new(()));
 
 #[derive(Clone, Debug)]
 pub struct Evaluate {
@@ -21,7 +33,32 @@ pub struct EvaluateConfig {
     pub api_key: String,
     pub prompt_path: PathBuf,
     pub prompt_dir: PathBuf,
-    pub quiet: bool
+    pub quiet: bool,
+    pub clean_env: bool,
+    pub confirm_commands: bool,
+    pub deny_commands: bool,
+    pub output_format: OutputFormat,
+    pub input: Option<String>,
+    pub deadline: Option<Duration>,
+    pub rate_limit: Option<RateLimit>
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Print the output of commands as is
+    #[default]
+    Text,
+    /// Print a JSON event per line for every prompt invocation and command output
+    Json
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum EvaluateEvent<'a> {
+    PromptStart { prompt: &'a str, input: Option<&'a str> },
+    PromptOutput { prompt: &'a str, ai: &'a str, user: &'a str },
+    CommandOutput { prompt: &'a str, command: &'a str, output: &'a str },
+    Print { prompt: &'a str, text: &'a str },
 }
 
 #[derive(Debug, Clone, Default)]
@@ -33,7 +70,9 @@ pub struct EvaluateState {
 #[derive(Debug, Default, Clone)]
 pub struct EvaluateVars {
     pub ai: String,
-    pub user: String
+    pub user: String,
+    /// Named capture groups of the match case that called this prompt
+    pub captures: HashMap<String, String>
 }
 
 #[derive(Debug)]
@@ -42,6 +81,8 @@ pub enum EvaluateError {
     MissingPrompt(String),
     UndeclaredVariable(String),
     JoinError(JoinError),
+    ContextLengthExceeded(String),
+    DeadlineExceeded(Duration),
     CommandExited
 }
 
@@ -62,15 +103,26 @@ impl Evaluate {
         };
 
         let main = evaluate.program.prompts.iter().find(|prompt| prompt.is_main).unwrap();
+
+        // Input given up front is the first user turn, so the AI can't respond first
+        let input = evaluate.config.input.clone();
+        let eager = match input {
+            Some(_) => Some(false),
+            None => main.options.eager
+        };
+
         let command = &ChatCommand {
             completion: CompletionOptions {
-                ai_responds_first: main.options.eager.clone(),
+                ai_responds_first: eager,
+                append: input,
                 no_context: main.options.history.clone().map(|h| !h),
                 name: Some(main.name.clone()),
                 once: Some(true),
                 prefix_ai: Some(main.name.clone()),
                 stream: Some(false),
                 quiet: Some(true),
+                tokens_balance: main.options.tokens_balance,
+                stop: main.options.stop.clone(),
                 ..CompletionOptions::default()
             },
             system: main.options.description.clone(),
@@ -78,68 +130,178 @@ impl Evaluate {
             provider: None
         };
 
-        evaluate_prompt(evaluate, main, command).await
+        let main = evaluate_prompt(evaluate, main, command, HashMap::new());
+        with_deadline(evaluate.config.deadline, main).await
+    }
+}
+
+/// Runs a prompt until the deadline, if there's one. Commands still running are killed when their
+/// prompt is dropped.
+async fn with_deadline(
+    deadline: Option<Duration>,
+    prompt: impl Future<Output = Result<(), EvaluateError>>) -> Result<(), EvaluateError>
+{
+    match deadline {
+        Some(deadline) => tokio::time::timeout(deadline, prompt)
+            .await
+            .unwrap_or(Err(EvaluateError::DeadlineExceeded(deadline))),
+        None => prompt.await
+    }
+}
+
+/// A spawned prompt call that's aborted once nothing is waiting on it anymore. Prompt calls spawn
+/// their own calls in turn, so dropping the main prompt cancels every call still in flight.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Spaces out requests to the API evenly. Clones share their schedule, so prompts running in
+/// parallel together stay under the limit.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    interval: Duration,
+    next: Arc<Mutex<Instant>>
+}
+
+impl RateLimit {
+    pub fn per_minute(requests: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(60) / requests,
+            next: Arc::new(Mutex::new(Instant::now()))
+        }
+    }
+
+    /// Takes the next free turn and waits for it to come.
+    async fn wait(&self) {
+        let turn = {
+            let mut next = self.next.lock().unwrap();
+            let turn = (*next).max(Instant::now());
+            *next = turn + self.interval;
+            turn
+        };
+
+        tokio::time::sleep_until(turn).await;
     }
 }
 
 async fn evaluate_prompt(
     evaluator: &Evaluate,
     prompt: &Prompt,
-    command: &ChatCommand) -> Result<(), EvaluateError>
+    command: &ChatCommand,
+    captures: HashMap<String, String>) -> Result<(), EvaluateError>
 {
     let Evaluate { client, config, .. } = evaluator;
 
+    emit_event(config, &EvaluateEvent::PromptStart {
+        prompt: &prompt.name,
+        input: command.completion.append.as_deref()
+    });
+
+    if let Some(rate_limit) = &config.rate_limit {
+        rate_limit.wait().await;
+    }
+
     let config = Config {
         dir: config.prompt_dir.clone(),
         ..Config::default()
     };
 
-    let result = command.run(client, &config).await.unwrap();
+    let result = match command.run(client, &config).await {
+        Err(ChatError::OpenAIError(e)) if e.error.code.as_deref() == Some("context_length_exceeded") => {
+            eprintln!(
+                "The conversation for `{}` no longer fits in the model's context. Try lowering its \
+                `tokens_balance` option, or disabling `history`.", prompt.name);
+
+            return Err(EvaluateError::ContextLengthExceeded(prompt.name.clone()));
+        },
+        result => result.unwrap()
+    };
 
     if result.len() == 0 {
         return Err(EvaluateError::CommandExited);
     }
 
+    let prefix_ai = command.completion.prefix_ai.as_deref().unwrap_or("AI");
+    let prefix_user = command.completion.prefix_user.as_deref().unwrap_or("USER");
+
     let state = EvaluateState {
         current_prompt_name: prompt.name.clone(),
         vars: EvaluateVars {
             ai: result.iter().rev()
                 .find(|message| message.role == ChatRole::Ai)
-                .map(|message| message.content.split_once(':')
-                    .map(|(_, content)| content.trim_start().to_string())
-                    .unwrap_or_else(|| message.content.to_string())
-                )
+                .map(|message| strip_role_prefix(&message.content, prefix_ai))
+                .map(|ai| truncate_to_words(ai, prompt.options.max_words))
                 .unwrap(),
             user: result.iter().rev()
                 .find(|message| message.role == ChatRole::User)
-                .map(|message| message.content.split_once(':')
-                    .map(|(_, content)| content.trim_start().to_string())
-                    .unwrap_or_else(|| message.content.to_string())
-                )
+                .map(|message| strip_role_prefix(&message.content, prefix_user))
                 .unwrap_or_default(),
+            captures
         }
     };
 
-    for statement in prompt.statements.iter() {
-        match statement {
-            Statement::MatchStatement(match_statement) => {
-                let _ = evaluate_match_statement(evaluator, &state, match_statement).await;
-            },
-            Statement::PipeStatement(pipe_statement) => {
-                let _ = evaluate_pipe_statement(evaluator, &state, pipe_statement, None, None)
-                    .await;
-            },
-            Statement::Command(command) => {
-                let result = evaluate_command(evaluator, &state, &command, None, None)?;
+    emit_event(&evaluator.config, &EvaluateEvent::PromptOutput {
+        prompt: &prompt.name,
+        ai: &state.vars.ai,
+        user: &state.vars.user
+    });
 
-                if !evaluator.config.quiet {
-                    println!("{result}");
-                }
+    evaluate_statements(evaluator, &state, &prompt.statements).await
+}
+
+fn evaluate_statements<'a>(
+    evaluator: &'a Evaluate,
+    state: &'a EvaluateState,
+    statements: &'a [Statement]) -> Pin<Box<dyn Future<Output = Result<(), EvaluateError>> + Send + 'a>>
+{
+    Box::pin(async move {
+        for statement in statements.iter() {
+            match statement {
+                Statement::MatchStatement(match_statement) => {
+                    let _ = evaluate_match_statement(evaluator, state, match_statement).await;
+                },
+                Statement::PipeStatement(pipe_statement) => {
+                    let _ = evaluate_pipe_statement(evaluator, state, pipe_statement, None, None)
+                        .await;
+                },
+                Statement::Command(command) => {
+                    let result = evaluate_command(evaluator, state, command, None, None).await?;
+                    print_output(evaluator, state, command, &result);
+                },
+                Statement::Print(print) => {
+                    let text = interpolate(&print.0, state)?;
+
+                    match evaluator.config.output_format {
+                        OutputFormat::Text => write_stdout(format!("{text}\n").as_bytes()),
+                        OutputFormat::Json => emit_event(&evaluator.config, &EvaluateEvent::Print {
+                            prompt: &state.current_prompt_name,
+                            text: &text
+                        })
+                    }
+                },
+                Statement::IfStatement(IfStatement { condition, statements }) => {
+                    if evaluate_condition(evaluator, state, condition).await? {
+                        evaluate_statements(evaluator, state, statements).await?;
+                    }
+                },
+                Statement::Comment(_) => {}
             }
         }
-    }
 
-    Ok(())
+        Ok(())
+    })
 }
 
 async fn evaluate_match_statement(
@@ -147,12 +309,8 @@ async fn evaluate_match_statement(
     state: &EvaluateState,
     statement: &MatchStatement) -> Result<(), EvaluateError>
 {
-    let MatchStatement { variable, cases } = statement;
-    let test = match &*variable.0 {
-        "AI" => state.vars.ai.clone(),
-        "USER" => state.vars.user.clone(),
-        _ => panic!("Unexpected variable")
-    };
+    let MatchStatement { subject, cases } = statement;
+    let test = evaluate_subject(evaluator, state, subject, None, None).await?;
 
     for case in cases {
         if let Some(captures) = case.regex.captures(&test) {
@@ -179,14 +337,13 @@ async fn evaluate_match_action(
         },
         MatchAction::Command(ref command) => {
             let result = evaluate_command(
-                evaluator, state, command, Some(captures), Some(capture_names))?;
-
-            if !evaluator.config.quiet {
-                println!("{result}");
-            }
+                evaluator, state, command, Some(captures), Some(capture_names)).await?;
+            print_output(evaluator, state, command, &result);
         },
         MatchAction::PromptCall(ref call) => {
-            evaluate_prompt_call(evaluator, &state, &call, &captures[1])
+            let named = named_captures(captures, capture_names.clone());
+
+            evaluate_prompt_call(evaluator, &state, &call, &captures[1], named)
                 .await
                 .into_iter()
                 .collect::<Result<Vec<Result<(), EvaluateError>>, JoinError>>()?
@@ -205,18 +362,14 @@ async fn evaluate_pipe_statement(
     captures: Option<&Captures<'_>>,
     capture_names: Option<&mut CaptureNames<'_>>) -> Result<(), EvaluateError>
 {
-    let append = match &statement.subject {
-        PipeSubject::Command(command) => {
-            evaluate_command(evaluator, state, command, captures, capture_names)?
-        },
-        PipeSubject::Variable(variable) =>  match &*variable.0 {
-            "AI" => state.vars.ai.to_string(),
-            "USER" => state.vars.user.to_string(),
-            _ => return Err(EvaluateError::UndeclaredVariable(variable.0.clone()))
-        }
+    let named = match (captures, &capture_names) {
+        (Some(captures), Some(capture_names)) => named_captures(captures, (*capture_names).clone()),
+        _ => HashMap::new()
     };
+    let append = evaluate_subject(evaluator, state, &statement.subject, captures, capture_names)
+        .await?;
 
-    evaluate_prompt_call(evaluator, &state, &statement.call, &append)
+    evaluate_prompt_call(evaluator, &state, &statement.call, &append, named)
         .await
         .into_iter()
         .collect::<Result<Vec<Result<(), EvaluateError>>, JoinError>>()?
@@ -230,7 +383,8 @@ fn evaluate_prompt_call(
     evaluator: &Evaluate,
     state: &EvaluateState,
     call: &PromptCall,
-    append: &str) -> Pin<Box<dyn Future<Output = Vec<Result<Result<(), EvaluateError>, JoinError>>> + Send + 'static>>
+    append: &str,
+    captures: HashMap<String, String>) -> Pin<Box<dyn Future<Output = Vec<Result<Result<(), EvaluateError>, JoinError>>> + Send + 'static>>
 {
     let mut handles = vec![];
 
@@ -241,14 +395,19 @@ fn evaluate_prompt_call(
             .ok_or(EvaluateError::MissingPrompt(name.clone().into()))
             .unwrap()
             .clone();
-        let append_str = Some(String::from(append));
+        // An eager prompt speaks first, so the first time it's called it opens its conversation
+        // instead of answering what it was given. From then on it answers like any other prompt.
+        let eager = prompt.options.eager.unwrap_or(false)
+            && (append.is_empty() || session_is_empty(&evaluator.config, &prompt.name));
+        let append_str = Some(String::from(append)).filter(|_| !eager);
         let prefix_user = Some(state.current_prompt_name.clone());
+        let captures = captures.clone();
 
-        handles.push(tokio::spawn(async move {
+        handles.push(AbortOnDrop(tokio::spawn(async move {
             let options = prompt.options.clone();
             let command = ChatCommand {
                 completion: CompletionOptions {
-                    ai_responds_first: Some(false),
+                    ai_responds_first: Some(eager),
                     append: append_str,
                     no_context: options.history.map(|h| !h),
                     name: Some(prompt.name.clone()),
@@ -257,32 +416,218 @@ fn evaluate_prompt_call(
                     prefix_user,
                     stream: Some(false),
                     quiet: Some(true),
+                    tokens_balance: options.tokens_balance,
+                    stop: options.stop,
                     ..CompletionOptions::default()
                 },
                 system: options.description,
                 direction: options.direction,
                 provider: None
             };
-            evaluate_prompt(&evaluate, &prompt, &command).await
-        }));
+            evaluate_prompt(&evaluate, &prompt, &command, captures).await
+        })));
     }
 
     Box::pin(join_all(handles))
 }
 
-fn evaluate_command(
+/// Whether a prompt's session has nothing said in it yet, in this run or an earlier one.
+fn session_is_empty(config: &EvaluateConfig, name: &str) -> bool {
+    let path = config.prompt_dir.join("sessions").join(name);
+
+    match std::fs::read_to_string(path) {
+        Ok(session) => session.split_once("<->")
+            .is_none_or(|(_, transcript)| transcript.trim().is_empty()),
+        Err(_) => true
+    }
+}
+
+/// The text a pipe or match works on, either a variable or the output of a command.
+async fn evaluate_subject(
+    evaluator: &Evaluate,
+    state: &EvaluateState,
+    subject: &PipeSubject,
+    captures: Option<&Captures<'_>>,
+    capture_names: Option<&mut CaptureNames<'_>>) -> Result<String, EvaluateError>
+{
+    match subject {
+        PipeSubject::Command(command) => {
+            // Prompts and regular expressions only take text, so the output is trimmed and any
+            // bytes that aren't valid UTF-8 become U+FFFD here
+            let output = evaluate_command(evaluator, state, command, captures, capture_names)
+                .await?;
+            Ok(String::from_utf8_lossy(output.trim_ascii()).into_owned())
+        },
+        PipeSubject::Stderr(command) => {
+            let output = evaluate_command_stderr(evaluator, state, command, captures, capture_names)
+                .await?;
+            Ok(String::from_utf8_lossy(&output).into_owned())
+        },
+        PipeSubject::Variable(variable) => Ok(lookup_variable(state, &variable.0)?.to_string())
+    }
+}
+
+/// The named groups of a match, so the prompts it calls can use them in their own commands.
+fn named_captures(captures: &Captures<'_>, names: CaptureNames<'_>) -> HashMap<String, String> {
+    names
+        .flatten()
+        .filter_map(|name| captures.name(name).map(|m| (name.to_string(), m.as_str().to_string())))
+        .collect()
+}
+
+fn lookup_variable<'a>(state: &'a EvaluateState, name: &str) -> Result<&'a str, EvaluateError> {
+    match name {
+        "AI" => Ok(&state.vars.ai),
+        "USER" => Ok(&state.vars.user),
+        _ => state.vars.captures.get(name)
+            .map(String::as_str)
+            .ok_or_else(|| EvaluateError::UndeclaredVariable(name.to_string()))
+    }
+}
+
+/// Replaces every `$VARIABLE` in the text with its value, and `\$` or `\\` with the character
+/// they escape.
+fn interpolate(text: &str, state: &EvaluateState) -> Result<String, EvaluateError> {
+    let variable = Regex::new(r"\\([\\$])|\$([a-zA-Z0-9_]+)").unwrap();
+    let mut interpolated = String::new();
+    let mut last = 0;
+
+    for captures in variable.captures_iter(text) {
+        let whole = captures.get(0).unwrap();
+
+        interpolated.push_str(&text[last..whole.start()]);
+        match captures.get(1) {
+            Some(escaped) => interpolated.push_str(escaped.as_str()),
+            None => interpolated.push_str(lookup_variable(state, &captures[2])?)
+        }
+        last = whole.end();
+    }
+
+    interpolated.push_str(&text[last..]);
+    Ok(interpolated)
+}
+
+/// Removes the `label:` a message was written with. Only the label at the very start of the
+/// message counts, in any case like ai-cli matches it, so content that merely contains a colon, or
+/// another role's label, is left as is.
+fn strip_role_prefix(content: &str, prefix: &str) -> String {
+    content.get(..prefix.len())
+        .filter(|label| label.eq_ignore_ascii_case(prefix))
+        .and_then(|_| content[prefix.len()..].strip_prefix(':'))
+        .map(|rest| rest.trim_start().to_string())
+        .unwrap_or_else(|| content.to_string())
+}
+
+/// Cuts a response off after its first `max_words` words, keeping the whitespace between them.
+fn truncate_to_words(mut content: String, max_words: Option<usize>) -> String {
+    let cut = max_words.and_then(|max_words| content.split_whitespace().nth(max_words));
+
+    if let Some(word) = cut {
+        let index = word.as_ptr() as usize - content.as_ptr() as usize;
+        content.truncate(index);
+        content.truncate(content.trim_end().len());
+    }
+
+    content
+}
+
+/// Runs a command for its stdout, exactly as it was written. Anything on stderr is a failure.
+async fn evaluate_command(
     env: &Evaluate,
     state: &EvaluateState,
     command: &Command,
     captures: Option<&Captures<'_>>,
-    capture_names: Option<&mut CaptureNames<'_>>) -> Result<String, EvaluateError>
+    capture_names: Option<&mut CaptureNames<'_>>) -> Result<Vec<u8>, EvaluateError>
+{
+    let Some(mut process) = command_process(env, state, command, captures, capture_names).await else {
+        return Ok(vec![]);
+    };
+    let output = process.output()
+        .await
+        .expect("failed to execute process");
+
+    let err = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    if err.len() > 0 {
+        Err(EvaluateError::Command(err))
+    } else {
+        Ok(output.stdout)
+    }
+}
+
+/// Runs a command for its stderr, which isn't treated as a failure here. Its stdout is ignored.
+async fn evaluate_command_stderr(
+    env: &Evaluate,
+    state: &EvaluateState,
+    command: &Command,
+    captures: Option<&Captures<'_>>,
+    capture_names: Option<&mut CaptureNames<'_>>) -> Result<Vec<u8>, EvaluateError>
 {
-    let mut process = process::Command::new(if cfg!(target_os = "windows") {
+    let Some(mut process) = command_process(env, state, command, captures, capture_names).await else {
+        return Ok(vec![]);
+    };
+    let output = process.output()
+        .await
+        .expect("failed to execute process");
+
+    Ok(output.stderr.trim_ascii().to_vec())
+}
+
+/// Runs the command of an if statement, it's true when the command exits successfully. A skipped
+/// command is false.
+async fn evaluate_condition(
+    env: &Evaluate,
+    state: &EvaluateState,
+    command: &Command) -> Result<bool, EvaluateError>
+{
+    let Some(mut process) = command_process(env, state, command, None, None).await else {
+        return Ok(false);
+    };
+    let output = process.output()
+        .await
+        .expect("failed to execute process");
+
+    Ok(output.status.success())
+}
+
+/// The process a command runs in, or nothing when the command is skipped because running commands
+/// is denied or it wasn't confirmed.
+async fn command_process(
+    env: &Evaluate,
+    state: &EvaluateState,
+    command: &Command,
+    captures: Option<&Captures<'_>>,
+    capture_names: Option<&mut CaptureNames<'_>>) -> Option<tokio::process::Command>
+{
+    if env.config.deny_commands {
+        eprintln!("Skipped `{}`, running commands is denied", command.0);
+        return None;
+    }
+
+    if env.config.confirm_commands && !confirm_command(command).await {
+        eprintln!("Skipped `{}`", command.0);
+        return None;
+    }
+
+    let mut process = tokio::process::Command::new(if cfg!(target_os = "windows") {
         "cmd"
     } else {
         "sh"
     });
 
+    // A command outliving its prompt, like one cut off by the deadline, is killed with it
+    process.kill_on_drop(true);
+
+    // Only the variables the program provides are passed on, along with PATH so commands are
+    // still found
+    if env.config.clean_env {
+        process.env_clear();
+
+        if let Some(path) = std::env::var_os("PATH") {
+            process.env("PATH", path);
+        }
+    }
+
+    process.envs(&state.vars.captures);
     process.env("AI", &state.vars.ai);
     process.env("USER", &state.vars.user);
     process.current_dir(env.config.prompt_dir.clone());
@@ -308,14 +653,57 @@ fn evaluate_command(
         _ => {}
     }
 
-    let output = process.output().expect("failed to execute process");
+    Some(process)
+}
 
-    let err = String::from_utf8_lossy(&output.stderr).trim().to_string();
-    if err.len() > 0 {
-        Err(EvaluateError::Command(err))
-    } else {
-        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+/// Asks on the terminal whether a command may run, anything other than yes declines it. The
+/// answer is read from the terminal itself rather than stdin, which may be piped input, and on a
+/// blocking thread so the deadline can still stop the program while it waits.
+async fn confirm_command(command: &Command) -> bool {
+    let _lock = CONFIRM_LOCK.lock().await;
+    let question = format!("Run `{}`? [y/N] ", command.0);
+    let terminal = if cfg!(target_os = "windows") { "CONIN$" } else { "/dev/tty" };
+
+    let answer = tokio::task::spawn_blocking(move || {
+        let mut answer = String::new();
+
+        eprint!("{question}");
+        File::open(terminal)
+            .and_then(|tty| BufReader::new(tty).read_line(&mut answer))
+            .map(|_| answer)
+    });
+
+    match answer.await {
+        Ok(Ok(answer)) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        _ => false
+    }
+}
+
+/// Writes a command's output to stdout. Text output is written untouched, without adding a newline,
+/// so binary or non UTF-8 output isn't mangled. In a JSON event it's trimmed text like a pipe's.
+fn print_output(evaluator: &Evaluate, state: &EvaluateState, command: &Command, output: &[u8]) {
+    if evaluator.config.quiet {
+        return;
     }
+
+    match evaluator.config.output_format {
+        OutputFormat::Text => write_stdout(output),
+        OutputFormat::Json => emit_event(&evaluator.config, &EvaluateEvent::CommandOutput {
+            prompt: &state.current_prompt_name,
+            command: &command.0,
+            output: &String::from_utf8_lossy(output.trim_ascii())
+        })
+    }
+}
+
+/// Writes an event as a single line of JSON when the JSON output format is used.
+fn emit_event(config: &EvaluateConfig, event: &EvaluateEvent) {
+    if config.output_format != OutputFormat::Json {
+        return;
+    }
+
+    let line = serde_json::to_string(event).expect("Events should always serialize to JSON");
+    write_stdout(format!("{line}\n").as_bytes());
 }
 
 #[cfg(test)]
@@ -331,11 +719,12 @@ mod tests {
             current_prompt_name: String::new(),
             vars: EvaluateVars {
                 user: "".into(),
-                ai: "Yes. Something else".into()
+                ai: "Yes. Something else".into(),
+                ..EvaluateVars::default()
             }
         };
         let statement = &MatchStatement {
-            variable: Variable("AI".into()),
+            subject: PipeSubject::Variable(Variable("AI".into())),
             cases: vec![
                 MatchCase {
                     regex: Regex::new("(?i:yes[^a-z]*(?P<FOOBAR>.+))").unwrap(),
@@ -359,11 +748,12 @@ mod tests {
             current_prompt_name: String::new(),
             vars: EvaluateVars {
                 user: "".into(),
-                ai: "Yes. Something else".into()
+                ai: "Yes. Something else".into(),
+                ..EvaluateVars::default()
             }
         };
         let statement = &MatchStatement {
-            variable: Variable("AI".into()),
+            subject: PipeSubject::Variable(Variable("AI".into())),
             cases: vec![
                 MatchCase {
                     regex: Regex::new("((?i)yes[^a-z]*(.+))").unwrap(),
@@ -378,6 +768,208 @@ mod tests {
         */
     }
 
+    #[test]
+    fn strip_role_prefix_only_at_start() {
+        assert_eq!(strip_role_prefix("mom: Who ate the frosting?", "mom"), "Who ate the frosting?");
+        assert_eq!(strip_role_prefix("Note: AI: is a label", "mom"), "Note: AI: is a label");
+        assert_eq!(strip_role_prefix("AI: hello", "mom"), "AI: hello");
+        assert_eq!(strip_role_prefix("mommy: hello", "mom"), "mommy: hello");
+        assert_eq!(strip_role_prefix("AI: AI: hello", "AI"), "AI: hello");
+        assert_eq!(strip_role_prefix("Mom: Clean up!", "mom"), "Clean up!");
+        assert_eq!(strip_role_prefix("MOMMY: hello", "mom"), "MOMMY: hello");
+        assert_eq!(strip_role_prefix("é: hello", "a"), "é: hello");
+    }
+
+    #[test]
+    fn truncate_at_word_boundary() {
+        assert_eq!(truncate_to_words("Clean up\nyour room, now!".into(), Some(3)), "Clean up\nyour");
+        assert_eq!(truncate_to_words("Clean up!  ".into(), Some(2)), "Clean up!  ");
+        assert_eq!(truncate_to_words("Clean up!".into(), Some(0)), "");
+        assert_eq!(truncate_to_words("Clean up!".into(), None), "Clean up!");
+    }
+
+    #[tokio::test]
+    #[cfg(not(target_os = "windows"))]
+    async fn evaluate_command_keeps_non_utf8_output() {
+        let env = &mock_evaluator();
+        let state = &EvaluateState::default();
+        let output = evaluate_command(env, state, &Command(r"printf '\377abc\n'".into()), None, None)
+            .await
+            .unwrap();
+
+        assert_eq!(output, b"\xffabc\n");
+        assert_eq!(String::from_utf8_lossy(&output), "\u{FFFD}abc\n");
+    }
+
+    #[tokio::test]
+    #[cfg(not(target_os = "windows"))]
+    async fn evaluate_command_with_clean_env() {
+        let mut env = mock_evaluator();
+        env.config.clean_env = true;
+
+        let state = &EvaluateState {
+            current_prompt_name: String::new(),
+            vars: EvaluateVars {
+                user: "".into(),
+                ai: "Hi".into(),
+                ..EvaluateVars::default()
+            }
+        };
+        let command = &Command(r#"echo "${HOME:-unset} $AI""#.into());
+
+        assert_eq!(evaluate_command(&env, state, command, None, None).await.unwrap(), b"unset Hi\n");
+    }
+
+    #[tokio::test]
+    async fn evaluate_command_when_commands_are_denied() {
+        let mut env = mock_evaluator();
+        env.config.deny_commands = true;
+        let state = &EvaluateState::default();
+
+        let result = evaluate_command(&env, state, &Command("echo denied".into()), None, None).await;
+        assert_eq!(result.unwrap(), b"");
+
+        let result = evaluate_condition(&env, state, &Command("true".into())).await;
+        assert!(!result.unwrap());
+    }
+
+    #[test]
+    fn session_is_empty_until_something_is_said() {
+        let mut env = mock_evaluator();
+        env.config.prompt_dir = std::env::temp_dir().join(format!("prompt-{}", std::process::id()));
+        let sessions = env.config.prompt_dir.join("sessions");
+        std::fs::create_dir_all(&sessions).unwrap();
+
+        assert!(session_is_empty(&env.config, "mom"));
+
+        std::fs::write(sessions.join("mom"), "name: mom\n<->\n").unwrap();
+        assert!(session_is_empty(&env.config, "mom"));
+
+        std::fs::write(sessions.join("mom"), "name: mom\n<->\nmom: Who ate the frosting?\n").unwrap();
+        assert!(!session_is_empty(&env.config, "mom"));
+
+        std::fs::remove_dir_all(&env.config.prompt_dir).unwrap();
+    }
+
+    #[test]
+    fn serialize_evaluate_events() {
+        let event = EvaluateEvent::CommandOutput { prompt: "table", command: "echo hi", output: "hi" };
+
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"command-output","prompt":"table","command":"echo hi","output":"hi"}"#
+        );
+    }
+
+    #[test]
+    fn interpolate_variables() {
+        let state = &EvaluateState {
+            current_prompt_name: String::new(),
+            vars: EvaluateVars {
+                user: "Hi".into(),
+                ai: "Hello".into(),
+                captures: HashMap::from([("ANIMAL".into(), "dog".into())])
+            }
+        };
+
+        assert_eq!(interpolate("$USER -> $AI!", state).unwrap(), "Hi -> Hello!");
+        assert_eq!(interpolate("$ANIMAL costs \\$5", state).unwrap(), "dog costs $5");
+        assert_eq!(interpolate(r"back\\slash \\$AI", state).unwrap(), r"back\slash \Hello");
+        assert!(matches!(
+            interpolate("$FOO", state),
+            Err(EvaluateError::UndeclaredVariable(name)) if name == "FOO"
+        ));
+    }
+
+    #[tokio::test]
+    #[cfg(not(target_os = "windows"))]
+    async fn evaluate_condition_uses_exit_status() {
+        let env = &mock_evaluator();
+        let state = &EvaluateState::default();
+
+        assert!(evaluate_condition(env, state, &Command("test 1 -eq 1".into())).await.unwrap());
+        assert!(!evaluate_condition(env, state, &Command("test 1 -eq 2".into())).await.unwrap());
+        assert!(!evaluate_condition(env, state, &Command("echo oops >&2; false".into())).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn abort_prompt_calls_when_dropped() {
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let call = AbortOnDrop(tokio::spawn(async move {
+            let _tx = tx;
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }));
+
+        drop(call);
+        assert!(rx.await.is_err());
+    }
+
+    #[tokio::test]
+    #[cfg(not(target_os = "windows"))]
+    async fn deadline_kills_running_commands() {
+        let env = &mock_evaluator();
+        let state = &EvaluateState::default();
+        let statements = &[Statement::Command(Command("sleep 5".into()))];
+        let start = Instant::now();
+
+        let result = with_deadline(
+            Some(Duration::from_millis(100)), evaluate_statements(env, state, statements)).await;
+
+        assert!(matches!(result, Err(EvaluateError::DeadlineExceeded(_))));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_spaces_out_requests() {
+        let rate_limit = RateLimit::per_minute(3000);
+        let start = Instant::now();
+
+        join_all((0..3).map(|_| {
+            let rate_limit = rate_limit.clone();
+            tokio::spawn(async move { rate_limit.wait().await })
+        })).await;
+
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[tokio::test]
+    #[cfg(not(target_os = "windows"))]
+    async fn evaluate_subject_with_command() {
+        let env = &mock_evaluator();
+        let state = &EvaluateState::default();
+        let subject = &PipeSubject::Command(Command("echo clean".into()));
+
+        assert_eq!(evaluate_subject(env, state, subject, None, None).await.unwrap(), "clean");
+    }
+
+    #[tokio::test]
+    #[cfg(not(target_os = "windows"))]
+    async fn evaluate_subject_with_stderr() {
+        let env = &mock_evaluator();
+        let state = &EvaluateState::default();
+        let subject = &PipeSubject::Stderr(Command("echo out; echo err >&2".into()));
+
+        assert_eq!(evaluate_subject(env, state, subject, None, None).await.unwrap(), "err");
+    }
+
+    #[tokio::test]
+    #[cfg(not(target_os = "windows"))]
+    async fn evaluate_command_with_inherited_captures() {
+        let env = &mock_evaluator();
+        let regex = Regex::new("(?P<ANIMAL>[a-z]+) (?P<SOUND>[a-z]+)?").unwrap();
+        let captures = regex.captures("dog ").unwrap();
+        let state = &EvaluateState {
+            current_prompt_name: String::new(),
+            vars: EvaluateVars {
+                captures: named_captures(&captures, regex.capture_names()),
+                ..EvaluateVars::default()
+            }
+        };
+        let command = &Command(r#"echo "$ANIMAL ${SOUND:-unset}""#.into());
+
+        assert_eq!(evaluate_command(env, state, command, None, None).await.unwrap(), b"dog unset\n");
+    }
+
     fn mock_evaluator() -> Evaluate {
         Evaluate {
             client: reqwest::ClientBuilder::new().build().expect("Client"),
@@ -385,10 +977,18 @@ mod tests {
                 api_key: String::new(),
                 prompt_path: PathBuf::new(),
                 prompt_dir: std::env::current_dir().unwrap(),
-                quiet: false
+                quiet: false,
+                clean_env: false,
+                confirm_commands: false,
+                deny_commands: false,
+                output_format: OutputFormat::Text,
+                input: None,
+                deadline: None,
+                rate_limit: None
             },
             program: Program {
-                prompts: vec![]
+                prompts: vec![],
+                comment: None
             }
         }
     }
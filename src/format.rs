@@ -0,0 +1,303 @@
+use super::ast::*;
+use super::parser::{parse, IMPLICIT_MAIN_PROMPT};
+use peg::{error::ParseError, str::LineCol};
+
+const INDENT: &str = "    ";
+
+#[derive(Debug)]
+pub enum FormatError {
+    Parse(ParseError<LineCol>),
+    Options(serde_yaml::Error),
+    LostComment
+}
+
+/// Parses a program and writes it back out in the canonical format. Comments are only kept above
+/// prompts, between statements and at the end, so a program with a comment anywhere else, like
+/// inside a match block, fails with `LostComment` instead of being rewritten without it.
+pub fn format_source(source: &str) -> Result<String, FormatError> {
+    let parse_source = |source: &str| parse::program(source)
+        .map_err(FormatError::Parse)?
+        .map_err(FormatError::Options);
+
+    let program = parse_source(source)?;
+
+    // Outside of comments a `#` can only be in the commands, regular expressions, strings and
+    // options the program holds as written, so one it doesn't hold was in a comment it dropped
+    if source.matches('#').count() > held_hashes(&program) {
+        return Err(FormatError::LostComment);
+    }
+
+    let formatted = format(&program);
+
+    // And the formatted program has to read back with the very same comments
+    if comments(&parse_source(&formatted)?) != comments(&program) {
+        return Err(FormatError::LostComment);
+    }
+
+    Ok(formatted)
+}
+
+pub fn format(program: &Program) -> String {
+    let mut out = String::new();
+
+    for (index, prompt) in program.prompts.iter().enumerate() {
+        if index > 0 {
+            out.push('\n');
+        }
+
+        if index == 0 && is_implicit_main(prompt) {
+            format_statements(&mut out, &prompt.statements, 0);
+            continue;
+        }
+
+        if let Some(Comment(comment)) = &prompt.comment {
+            format_comment(&mut out, comment, 0);
+        }
+
+        if prompt.header.is_empty() {
+            out.push_str(&format!("{} {{\n", prompt.name));
+        } else {
+            out.push_str(&format!("{}\n", prompt.name));
+            for line in prompt.header.lines() {
+                push_line(&mut out, line.trim_end(), 1);
+            }
+            out.push_str("{\n");
+        }
+        format_statements(&mut out, &prompt.statements, 1);
+        out.push_str("}\n");
+    }
+
+    if let Some(Comment(comment)) = &program.comment {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        format_comment(&mut out, comment, 0);
+    }
+
+    out
+}
+
+/// Every comment in a program, in order.
+fn comments(program: &Program) -> Vec<&Comment> {
+    fn statement_comments<'a>(statements: &'a [Statement], comments: &mut Vec<&'a Comment>) {
+        for statement in statements {
+            match statement {
+                Statement::Comment(comment) => comments.push(comment),
+                Statement::IfStatement(IfStatement { statements, .. }) => {
+                    statement_comments(statements, comments)
+                },
+                _ => {}
+            }
+        }
+    }
+
+    let mut comments = vec![];
+    for prompt in program.prompts.iter() {
+        comments.extend(prompt.comment.iter());
+        statement_comments(&prompt.statements, &mut comments);
+    }
+    comments.extend(program.comment.iter());
+
+    comments
+}
+
+/// How many `#` the parsed program holds, in its comments or anywhere else.
+fn held_hashes(program: &Program) -> usize {
+    let trailing = program.comment.as_ref().map_or(0, |Comment(comment)| hashes(comment));
+
+    program.prompts.iter()
+        .map(|prompt| {
+            hashes(&prompt.header)
+                + prompt.comment.as_ref().map_or(0, |Comment(comment)| hashes(comment))
+                + statement_hashes(&prompt.statements)
+        })
+        .sum::<usize>() + trailing
+}
+
+fn statement_hashes(statements: &[Statement]) -> usize {
+    let subject_hashes = |subject: &PipeSubject| match subject {
+        PipeSubject::Command(Command(command)) | PipeSubject::Stderr(Command(command)) => {
+            hashes(command)
+        },
+        PipeSubject::Variable(_) => 0
+    };
+
+    statements.iter()
+        .map(|statement| match statement {
+            Statement::MatchStatement(MatchStatement { subject, cases }) => {
+                subject_hashes(subject) + cases.iter()
+                    .map(|case| hashes(case.regex.as_str()) + match &case.action {
+                        MatchAction::Pipe(pipe) => subject_hashes(&pipe.subject),
+                        MatchAction::Command(Command(command)) => hashes(command),
+                        MatchAction::PromptCall(_) => 0
+                    })
+                    .sum::<usize>()
+            },
+            Statement::PipeStatement(pipe) => subject_hashes(&pipe.subject),
+            Statement::Command(Command(command)) => hashes(command),
+            Statement::Print(Print(text)) => hashes(text),
+            Statement::IfStatement(IfStatement { condition: Command(command), statements }) => {
+                hashes(command) + statement_hashes(statements)
+            },
+            Statement::Comment(Comment(comment)) => hashes(comment)
+        })
+        .sum()
+}
+
+fn hashes(text: &str) -> usize {
+    text.matches('#').count()
+}
+
+// Written as top level statements, a prompt has to come out of the parser the same way again
+fn is_implicit_main(prompt: &Prompt) -> bool {
+    prompt.is_main
+        && prompt.name == IMPLICIT_MAIN_PROMPT
+        && prompt.header.is_empty()
+        && prompt.comment.is_none()
+        && prompt.statements.iter().any(|statement| !matches!(statement, Statement::Comment(_)))
+}
+
+fn format_statements(out: &mut String, statements: &[Statement], depth: usize) {
+    for statement in statements {
+        match statement {
+            Statement::MatchStatement(MatchStatement { subject, cases }) => {
+                if cases.is_empty() {
+                    push_line(out, &format!("match {} {{}}", format_subject(subject)), depth);
+                    continue;
+                }
+
+                push_line(out, &format!("match {} {{", format_subject(subject)), depth);
+                for (index, case) in cases.iter().enumerate() {
+                    let separator = if index + 1 < cases.len() { "," } else { "" };
+                    let action = match &case.action {
+                        MatchAction::Pipe(pipe) => format_pipe(pipe),
+                        MatchAction::Command(command) => format_command(command),
+                        MatchAction::PromptCall(call) => call.names.join(", ")
+                    };
+
+                    let case = format!("{} => {action}{separator}", case.regex.as_str());
+                    push_line(out, &case, depth + 1);
+                }
+                push_line(out, "}", depth);
+            },
+            Statement::PipeStatement(pipe) => push_line(out, &format_pipe(pipe), depth),
+            Statement::Command(command) => push_line(out, &format_command(command), depth),
+            Statement::Print(Print(text)) => {
+                let text = text.replace('"', "\\\"");
+                push_line(out, &format!("print \"{text}\""), depth);
+            },
+            Statement::IfStatement(IfStatement { condition, statements }) => {
+                push_line(out, &format!("if {} {{", format_command(condition)), depth);
+                format_statements(out, statements, depth + 1);
+                push_line(out, "}", depth);
+            },
+            Statement::Comment(Comment(comment)) => format_comment(out, comment, depth)
+        }
+    }
+}
+
+fn format_comment(out: &mut String, comment: &str, depth: usize) {
+    for line in comment.split('\n') {
+        push_line(out, line, depth);
+    }
+}
+
+fn format_pipe(pipe: &PipeStatement) -> String {
+    format!("{} -> {}", format_subject(&pipe.subject), pipe.call.names.join(", "))
+}
+
+fn format_subject(subject: &PipeSubject) -> String {
+    match subject {
+        PipeSubject::Command(command) => format_command(command),
+        PipeSubject::Stderr(command) => format!("{} 2>", format_command(command)),
+        PipeSubject::Variable(Variable(name)) => format!("${name}")
+    }
+}
+
+fn format_command(Command(command): &Command) -> String {
+    format!("`{command}`")
+}
+
+// Only the start of the line is indented, so the inside of a command spanning several lines is
+// left exactly as it was written
+fn push_line(out: &mut String, line: &str, depth: usize) {
+    if !line.is_empty() {
+        out.push_str(&INDENT.repeat(depth));
+    }
+    out.push_str(line);
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_program() {
+        let source = r#"
+# Says hi
+
+# to bob
+bob
+        direction: >
+          Say hi.
+{
+  `echo $AI`   # shout it
+    match $AI {
+    (hi)=>`echo yes`,(.*)   =>   alice
+    }
+  if `true` { print "a \"quote\"" }
+}
+alice {}
+        "#;
+
+        assert_eq!(format_source(source).unwrap(), r#"# Says hi
+
+# to bob
+bob
+    direction: >
+      Say hi.
+{
+    `echo $AI`
+    # shout it
+    match $AI {
+        (hi) => `echo yes`,
+        (.*) => alice
+    }
+    if `true` {
+        print "a \"quote\""
+    }
+}
+
+alice {
+}
+"#);
+    }
+
+    #[test]
+    fn format_is_stable() {
+        let source = "# One liner\n$AI -> bob\n\nbob {\n    `echo $AI`\n}\n";
+
+        assert_eq!(format_source(source).unwrap(), source);
+        for path in ["examples/animal_house/animal_house.pr", "examples/silly_terminal/silly.pr",
+            "examples/table/table.pr"] {
+            let source = std::fs::read_to_string(path).unwrap();
+
+            assert_eq!(format_source(&source).unwrap(), source, "{path}");
+        }
+    }
+
+    #[test]
+    fn format_keeps_trailing_comments() {
+        let source = "bob {\n    `echo '#1'`\n}\n# trailing\n\n";
+
+        assert_eq!(format_source(source).unwrap(), "bob {\n    `echo '#1'`\n}\n\n# trailing\n");
+    }
+
+    #[test]
+    fn format_refuses_to_drop_comments() {
+        let source = "bob {\n    match $AI {\n        # never\n        (no) => alice\n    }\n}\n";
+
+        assert!(matches!(format_source(source), Err(FormatError::LostComment)));
+    }
+}
@@ -2,46 +2,139 @@ pub mod ast;
 pub mod parser;
 pub mod eval;
 pub mod watch;
+pub mod format;
+pub mod output;
 
 use clap::Parser;
-use reqwest::{ClientBuilder,header::HeaderMap,header::HeaderValue};
-use eval::{Evaluate,EvaluateError,EvaluateConfig};
+use regex::Regex;
+use reqwest::{ClientBuilder,header::HeaderMap,header::HeaderName,header::HeaderValue};
+use ast::Program;
+use eval::{Evaluate,EvaluateError,EvaluateConfig,OutputFormat,RateLimit};
+use format::FormatError;
 use std::fs;
 use std::env;
+use std::io::{self,IsTerminal,Read};
+use std::backtrace::{Backtrace,BacktraceStatus};
 use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct PromptArgs {
-    /// Path to the main prompt file
-    pub path: PathBuf,
+    /// Path to the main prompt file, optionally followed by more files whose prompts are added to
+    /// the same program
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
 
     /// What session file to watch, if any
     #[arg(long, short)]
     pub watch: Option<String>,
 
+    /// Only print the last n lines of the watched session's transcript before following it
+    #[arg(long, requires = "watch")]
+    pub tail: Option<usize>,
+
     /// By default, we output the result of commands in a final position to stdout (the terminal).
     /// This will suppress that output.
     #[arg(long, short, default_value_t = false)]
-    pub quiet: bool
+    pub quiet: bool,
+
+    /// Run the shell commands in a program with an empty environment, only passing along PATH and
+    /// the variables the program provides, like $AI and $USER.
+    #[arg(long, default_value_t = false)]
+    pub clean_env: bool,
+
+    /// Print each shell command a program wants to run and ask before running it. A declined
+    /// command is skipped, it outputs nothing and as the condition of an if it's false.
+    #[arg(long, default_value_t = false)]
+    pub confirm_commands: bool,
+
+    /// Refuse to run any shell command in the program, skipping each one like a declined command.
+    #[arg(long, default_value_t = false, conflicts_with = "confirm_commands")]
+    pub deny_commands: bool,
+
+    /// How the program's output is written to stdout. The json format emits one event per line for
+    /// every prompt invocation, its output, and the output of commands.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+
+    /// Stop the program after this many seconds, cancelling any prompts that are still running.
+    #[arg(long, value_name = "SECONDS")]
+    pub deadline: Option<u64>,
+
+    /// Extra HTTP header sent with every request, given as "Key: Value". Can be repeated, and
+    /// overrides the default headers of the same name, except Authorization, which is always set
+    /// from OPEN_AI_API_KEY.
+    #[arg(long = "header", value_name = "HEADER")]
+    pub headers: Vec<String>,
+
+    /// Only speak HTTP/1.1 to the API, for proxies that break HTTP/2 negotiation.
+    #[arg(long, default_value_t = false)]
+    pub http1_only: bool,
+
+    /// Wait between requests to the API so the program makes at most this many per minute, even
+    /// with prompts running in parallel.
+    #[arg(long, value_name = "REQUESTS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub requests_per_minute: Option<u32>,
+
+    /// Rewrite the given prompt files in the canonical format instead of running them.
+    #[arg(long, default_value_t = false)]
+    pub fmt: bool,
+
+    /// With --fmt, only list the files that aren't formatted, and exit with 1 if there are any.
+    #[arg(long, default_value_t = false, requires = "fmt")]
+    pub check: bool
 }
 
 pub async fn prompt(args: PromptArgs) {
-    let api_key = env::var("OPEN_AI_API_KEY")
-        .expect("OPEN_AI_API_KEY environment variable is missing");
+    if args.fmt {
+        format_files(&args.paths, args.check);
+        return;
+    }
+
+    // An empty key would only fail later, with a 401 from the API
+    let api_key = env::var("OPEN_AI_API_KEY").ok()
+        .filter(|key| !key.trim().is_empty())
+        .expect("OPEN_AI_API_KEY environment variable is missing or empty");
+
+    // Errors from the API can echo parts of a request back, and end up in panic messages. The
+    // report is written like the default one, with the thread and a backtrace if RUST_BACKTRACE
+    // asks for it.
+    let secret = api_key.clone();
+    std::panic::set_hook(Box::new(move |info| {
+        let thread = thread::current();
+        let backtrace = Backtrace::capture();
+        let report = match backtrace.status() {
+            BacktraceStatus::Captured => format!("{info}\nstack backtrace:\n{backtrace}"),
+            _ => format!("{info}\nnote: run with `RUST_BACKTRACE=1` environment variable to display \
+                a backtrace")
+        };
+
+        eprintln!("thread '{}' {}", thread.name().unwrap_or("<unnamed>"), redact(&report, &secret));
+    }));
 
     let client = {
         let mut headers = HeaderMap::new();
         headers.insert("Content-Type", HeaderValue::from_static("application/json"));
         headers.insert("Authorization", HeaderValue::from_str(&("Bearer ".to_owned() + &api_key)).unwrap());
 
-        ClientBuilder::new()
-            .default_headers(headers)
-            .build()
+        for header in args.headers.iter() {
+            let (key, value) = parse_header(header).unwrap_or_else(|e| panic!("{e}"));
+            headers.insert(key, value);
+        }
+
+        let mut builder = ClientBuilder::new().default_headers(headers);
+        if args.http1_only {
+            builder = builder.http1_only();
+        }
+
+        builder.build()
             .expect("Failed to construct http client")
     };
 
-    let prompt_dir = args.path.parent()
+    let prompt_path = args.paths[0].clone();
+    let prompt_dir = prompt_path.parent()
         .expect("Prompt file must have a parent directory")
         .to_path_buf();
 
@@ -50,23 +143,51 @@ pub async fn prompt(args: PromptArgs) {
         _ => prompt_dir
     };
 
-    let config = EvaluateConfig {
-        api_key,
-        prompt_dir,
-        prompt_path: args.path,
-        quiet: args.quiet
-    };
+    let mut program = Program { prompts: vec![], comment: None };
+    for path in args.paths.iter() {
+        if !path.is_file() {
+            panic!("prompt path {} is not a file", path.display());
+        }
+
+        let file = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Failed to open {}: {e}", path.display()));
 
-    if !config.prompt_path.is_file() {
-        panic!("prompt path is not a file");
+        let parsed = parser::parse::program(&file)
+            .expect("Couldn't parse the prompt program correctly")
+            .expect("Couldn't parse the prompt program correctly");
+
+        if let Err(name) = program.merge(parsed) {
+            eprintln!("The prompt {name} is defined more than once");
+            std::process::exit(1);
+        }
     }
 
-    let file = fs::read_to_string(&config.prompt_path)
-        .expect(&format!("Failed to open {}", "fooabr"));
+    // Piped input becomes the main prompt's first message instead of asking for it interactively.
+    // An eager main prompt never asks, so stdin is left alone, as it may never be closed.
+    let eager = program.prompts.iter().find(|prompt| prompt.is_main)
+        .and_then(|main| main.options.eager)
+        .unwrap_or(false);
+    let input = if eager || io::stdin().is_terminal() {
+        None
+    } else {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input).expect("Failed to read from stdin");
+        Some(input.trim().to_string()).filter(|input| !input.is_empty())
+    };
 
-    let program = parser::parse::program(&file)
-        .expect("Couldn't parse the prompt program correctly")
-        .expect("Couldn't parse the prompt program correctly");
+    let config = EvaluateConfig {
+        api_key,
+        prompt_dir,
+        prompt_path,
+        quiet: args.quiet,
+        clean_env: args.clean_env,
+        confirm_commands: args.confirm_commands,
+        deny_commands: args.deny_commands,
+        output_format: args.output_format,
+        input,
+        deadline: args.deadline.map(Duration::from_secs),
+        rate_limit: args.requests_per_minute.map(RateLimit::per_minute)
+    };
 
     let session_dir = config.prompt_dir.join("sessions");
     let prompts = program.prompts.clone();
@@ -74,12 +195,18 @@ pub async fn prompt(args: PromptArgs) {
     fs::create_dir_all(&session_dir)
         .expect("A sessions directory could not be created");
 
+    let secret = config.api_key.clone();
     let eval = tokio::spawn(async move {
         let eval = Evaluate::new(client, program, config);
         if let Err(e) = eval.eval().await {
             match e {
                 EvaluateError::CommandExited => std::process::exit(0),
-                _ => eprintln!("{:#?}", e)
+                EvaluateError::ContextLengthExceeded(_) => std::process::exit(1),
+                EvaluateError::DeadlineExceeded(deadline) => {
+                    eprintln!("Stopped the program after its {}s deadline", deadline.as_secs());
+                    std::process::exit(1);
+                },
+                _ => eprintln!("{}", redact(&format!("{:#?}", e), &eval.config.api_key))
             }
         }
     });
@@ -92,13 +219,132 @@ pub async fn prompt(args: PromptArgs) {
                 std::process::exit(0);
             }
             let watched = session_dir.join(watch);
-            watch::monitor(watched).await.unwrap();
+            watch::monitor(watched, args.tail).await.unwrap();
         },
         None => {
             if let Err(e) = eval.await {
-                eprintln!("{:#?}", e)
+                eprintln!("{}", redact(&format!("{:#?}", e), &secret))
             }
         }
     }
 
 }
+
+/// Reads a header given on the command line as "Key: Value", with any whitespace around the key
+/// and the value left out.
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (key, value) = header.split_once(':')
+        .ok_or_else(|| format!("Header {header:?} should be formatted as \"Key: Value\""))?;
+    let key = HeaderName::from_bytes(key.trim().as_bytes())
+        .map_err(|_| format!("Invalid header name in {header:?}"))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|_| format!("Invalid header value in {header:?}"))?;
+
+    Ok((key, value))
+}
+
+/// Hides the API key, and anything else shaped like an OpenAI key, in text about to be printed.
+fn redact(text: &str, api_key: &str) -> String {
+    let keys = Regex::new(r"sk-[A-Za-z0-9_-]{16,}").unwrap();
+    keys.replace_all(&text.replace(api_key, "[redacted]"), "[redacted]").into_owned()
+}
+
+fn format_files(paths: &[PathBuf], check: bool) {
+    let mut unformatted = false;
+
+    for path in paths {
+        let file = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Failed to open {}: {e}", path.display()));
+
+        let formatted = match format::format_source(&file) {
+            Ok(formatted) => formatted,
+            Err(FormatError::LostComment) => {
+                eprintln!("{} has a comment that formatting would remove, like one inside a match \
+                    block", path.display());
+                std::process::exit(1);
+            },
+            Err(e) => {
+                eprintln!("Couldn't parse {}: {:#?}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+
+        if formatted == file {
+            continue;
+        }
+
+        if check {
+            output::write_stdout(format!("{}\n", path.display()).as_bytes());
+            unformatted = true;
+        } else {
+            fs::write(path, formatted)
+                .unwrap_or_else(|e| panic!("Failed to write {}: {e}", path.display()));
+        }
+    }
+
+    if unformatted {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_header_key_and_value() {
+        let (key, value) = parse_header("X-Project: prompt").unwrap();
+
+        assert_eq!(key, "x-project");
+        assert_eq!(value, "prompt");
+    }
+
+    #[test]
+    fn parse_header_trims_whitespace() {
+        let (key, value) = parse_header("  X-Project :   two words  ").unwrap();
+
+        assert_eq!(key, "x-project");
+        assert_eq!(value, "two words");
+    }
+
+    #[test]
+    fn parse_header_without_colon() {
+        assert_eq!(
+            parse_header("X-Project prompt").unwrap_err(),
+            "Header \"X-Project prompt\" should be formatted as \"Key: Value\""
+        );
+    }
+
+    #[test]
+    fn parse_header_invalid_name() {
+        assert_eq!(
+            parse_header("X Project: prompt").unwrap_err(),
+            "Invalid header name in \"X Project: prompt\""
+        );
+    }
+
+    #[test]
+    fn redact_configured_key() {
+        let api_key = "project-key-1234";
+
+        assert_eq!(
+            redact("Invalid key project-key-1234 for project-key-1234", api_key),
+            "Invalid key [redacted] for [redacted]"
+        );
+    }
+
+    #[test]
+    fn redact_other_openai_keys() {
+        assert_eq!(
+            redact("Incorrect API key provided: sk-abcdefghijklmnop1234_-XY.", "project-key-1234"),
+            "Incorrect API key provided: [redacted]."
+        );
+    }
+
+    #[test]
+    fn redact_nothing_without_keys() {
+        let text = "Couldn't parse the prompt program: sk-short is not a key";
+
+        assert_eq!(redact(text, "project-key-1234"), text);
+    }
+}
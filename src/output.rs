@@ -0,0 +1,13 @@
+use std::io::{self,Write};
+use std::process;
+
+/// Writes to stdout, or quietly ends the program when whatever was reading it has gone away, like
+/// `head` once it has all the lines it wants. Exiting also drops any requests still in flight.
+pub fn write_stdout(bytes: &[u8]) {
+    let mut stdout = io::stdout().lock();
+
+    match stdout.write_all(bytes).and_then(|_| stdout.flush()) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => process::exit(0),
+        result => result.expect("Failed to write to stdout")
+    }
+}
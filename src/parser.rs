@@ -1,6 +1,12 @@
 use super::ast::*;
 use regex::Regex;
 
+/// Name given to the prompt that holds statements written outside of any named prompt.
+pub const IMPLICIT_MAIN_PROMPT: &str = "main";
+
+/// The most stop sequences the API takes, after ai-cli splits each one on commas.
+pub const MAX_STOPS: usize = 4;
+
 peg::parser! {
     pub grammar parse() for str {
         rule variable_char() -> String
@@ -28,9 +34,19 @@ peg::parser! {
                 Command(command_body.to_string())
             }
 
+        pub rule print_statement() -> Print
+            = "print" _ "\"" text:(("\\" "\"" { "\"" }) / $("\\" ['\\' | '$']) / $([^'"' | '\\']))* "\"" {
+                Print(text.concat())
+            }
+
+        pub rule if_statement() -> IfStatement
+            = "if" _ condition:command() _ "{" statements:statements() "}" {
+                IfStatement { condition, statements }
+            }
+
         pub rule match_statement() -> MatchStatement
-            = "match" _ variable:variable() _ "{" cases:match_cases() "}" _ {
-                MatchStatement { variable, cases }
+            = "match" _ subject:pipe_subject() _ "{" cases:match_cases() "}" {
+                MatchStatement { subject, cases }
             }
 
         rule match_cases() -> Vec<MatchCase>
@@ -47,12 +63,14 @@ peg::parser! {
                 MatchCase { regex, action: MatchAction::PromptCall(prompt_call) } 
             }
 
+        pub rule pipe_subject() -> PipeSubject
+            = command:command() _ "2>" { PipeSubject::Stderr(command) }
+            / command:command() { PipeSubject::Command(command) }
+            / variable:variable() { PipeSubject::Variable(variable) }
+
         pub rule pipe_statement() -> PipeStatement
-            = subject:command() _ "->" _ call:prompt_call() {
-                PipeStatement { call, subject: PipeSubject::Command(subject) }
-            }
-            / subject:variable() _ "->" _ call:prompt_call() {
-                PipeStatement { call, subject: PipeSubject::Variable(subject) }
+            = subject:pipe_subject() _ "->" _ call:prompt_call() {
+                PipeStatement { call, subject }
             }
 
         pub rule prompt_name() -> String
@@ -64,68 +82,142 @@ peg::parser! {
             }
 
         pub rule prompt() -> Result<Prompt, serde_yaml::Error>
-            = _ name:prompt_name() yaml:$([^'{']*) _ "{" _ statements:statements() _ "}" _ {
-                let mut indent = None;
-                let yaml = yaml
-                    .to_string()
-                    .lines()
-                    .map(|line| {
-                        if indent.is_none() {
-                            indent = line.chars().enumerate().find_map(|(i, c)| if c != ' ' {
-                                Some(i)
-                            } else {
-                                None
-                            });
-
-                            if indent == Some(0) {
-                                indent = None;
-                            }
-                        }
-                        let strip = indent.unwrap_or(0);
-                        if line.len() > strip {
-                            format!("{}\n", line[strip..].to_string())
-                        } else {
-                            format!("{}\n", line)
-                        }
-                    })
-                    .collect::<String>();
-                    
+            = __() comment:comment_block()? __() name:prompt_name() yaml:$([^'{']*) "{" statements:statements() "}" __() {
+                let yaml = dedent(yaml);
+
                 let options = match yaml.len() {
                     0 => PromptOptions::default(),
                     _ => serde_yaml::from_str(&yaml)?
                 };
+                check_stops(&name, &options)?;
 
-                Ok(Prompt { name, options, statements, is_main: false })
+                let header = yaml.trim_start_matches('\n').trim_end().to_string();
+
+                Ok(Prompt { name, options, header, comment, statements, is_main: false })
             }
 
         pub rule statement() -> Statement
-            = s:match_statement() _ { Statement::MatchStatement(s) }
-            / s:pipe_statement() _ { Statement::PipeStatement(s) }
-            / s:command() _ { Statement::Command(s) }
+            = s:match_statement() { Statement::MatchStatement(s) }
+            / s:if_statement() { Statement::IfStatement(s) }
+            / s:pipe_statement() { Statement::PipeStatement(s) }
+            / s:command() { Statement::Command(s) }
+            / s:print_statement() { Statement::Print(s) }
+            / s:comment_block() { Statement::Comment(s) }
 
         pub rule statements() -> Vec<Statement>
-            = _ statements:(statement()) ** _ { statements }
+            = __() statements:(statement()) ** __() __() { statements }
 
         pub rule program() -> Result<Program, serde_yaml::Error>
-            = _ prompts:prompt()* _ {
-                let mut prompts = prompts
-                    .into_iter()
-                    .collect::<Result<Vec<_>, serde_yaml::Error>>()?;
-
-                if let Some(mut prompt) = prompts.first_mut() {
-                    prompt.is_main = true;
+            = bom()? statements:statements() prompts:prompt()* __() comment:comment_block()? _ {?
+                match prompts.into_iter().collect::<Result<Vec<_>, serde_yaml::Error>>() {
+                    Ok(prompts) => build_program(statements, prompts, comment).map(Ok),
+                    Err(e) => Ok(Err(e))
                 }
-
-                Ok(Program { prompts })
             }
 
+        // Editors on Windows tend to start UTF-8 files with a byte order mark
+        rule bom() = "\u{feff}"
+
         rule _() = quiet!{__() comment()* __()}
         rule __() = quiet!{[' ' | '\t' | '\r' | '\n']*}
         rule ___() = quiet!{"\r\n" / "\n" / "\r"}
 
         rule comment()
-            = "#" $(!___()[_])+ ___()?
+            = comment_line() ___()?
+
+        rule comment_line() -> &'input str
+            = $("#" (!___()[_])+)
+
+        pub rule comment_block() -> Comment
+            = first:comment_line() rest:(gap:$(__()) line:comment_line() { (gap, line) })*
+              blank:(&(___() [' ' | '\t']* ___()))? {
+                let mut text = first.trim_end().to_string();
+                for (gap, line) in rest {
+                    if gap.matches('\n').count() > 1 {
+                        text.push('\n');
+                    }
+                    text.push('\n');
+                    text.push_str(line.trim_end());
+                }
+                if blank.is_some() {
+                    text.push('\n');
+                }
+                Comment(text)
+            }
+    }
+}
+
+/// Puts a program's prompts together, with the first one as main. Statements written before any
+/// prompt become the body of an implicit main prompt, unless they're only the comments above the
+/// first prompt. A prompt can't also be named main then, as it could never be called.
+fn build_program(
+    statements: Vec<Statement>,
+    mut prompts: Vec<Prompt>,
+    comment: Option<Comment>) -> Result<Program, &'static str>
+{
+    let is_comment = |statement: &Statement| matches!(statement, Statement::Comment(_));
+
+    if statements.iter().any(|statement| !is_comment(statement)) {
+        if prompts.iter().any(|prompt| prompt.name == IMPLICIT_MAIN_PROMPT) {
+            return Err("top level statements or a prompt named main, not both");
+        }
+
+        prompts.insert(0, Prompt {
+            is_main: false,
+            name: IMPLICIT_MAIN_PROMPT.into(),
+            options: PromptOptions::default(),
+            header: String::new(),
+            comment: None,
+            statements
+        });
+    } else if let Some(prompt) = prompts.first_mut() {
+        if let Some(Statement::Comment(comment)) = statements.into_iter().last() {
+            prompt.comment = Some(comment);
+        }
+    }
+
+    if let Some(prompt) = prompts.first_mut() {
+        prompt.is_main = true;
+    }
+
+    // Nothing follows the comments at the end, so a blank line after them doesn't separate anything
+    let comment = comment.map(|Comment(comment)| Comment(comment.trim_end().to_string()));
+
+    Ok(Program { prompts, comment })
+}
+
+/// Refuses more stop sequences than the API takes, counted the way ai-cli sends them, so a
+/// prompt doesn't fail only once it runs.
+fn check_stops(name: &str, options: &PromptOptions) -> Result<(), serde_yaml::Error> {
+    let stops = options.stop.iter().flatten()
+        .map(|stop| stop.split(',').count())
+        .sum::<usize>();
+
+    if stops > MAX_STOPS {
+        return Err(serde::de::Error::custom(format!(
+            "prompt {name} has {stops} stop sequences once split on commas, at most {MAX_STOPS} are \
+            allowed")));
     }
+
+    Ok(())
+}
+
+/// Removes the indentation of the first non blank line from every line of a prompt's YAML options.
+/// Tabs and spaces each count as one column, and a line indented less than that only loses the
+/// whitespace it has, so the slicing always lands on a character boundary.
+fn dedent(yaml: &str) -> String {
+    let is_indent = |c: &char| *c == ' ' || *c == '\t';
+    let indent = yaml.lines()
+        .find(|line| !line.trim().is_empty())
+        .map(|line| line.chars().take_while(is_indent).count())
+        .unwrap_or(0);
+
+    yaml.lines()
+        .map(|line| {
+            let strip = line.chars().take(indent).take_while(is_indent).count();
+            format!("{}\n", &line[strip..])
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -149,25 +241,113 @@ mod tests {
                     is_main: true,
                     name: "bob".into(),
                     options: PromptOptions::default(),
-                    statements: vec![]
+                    header: String::new(),
+                    comment: Some(Comment("# Comments".into())),
+                    statements: vec![
+                        Statement::Comment(Comment("# bob was a nice guy".into()))
+                    ]
                 },
                 Prompt {
                     is_main: false,
                     name: "alice".into(),
                     options: PromptOptions::default(),
+                    header: String::new(),
+                    comment: None,
                     statements: vec![]
                 },
-            ]
+            ],
+            comment: None
+        });
+    }
+
+    #[test]
+    fn parse_program_with_byte_order_mark() {
+        let program = "\u{feff}# Saved on Windows\nbob {\n}\n";
+
+        assert_eq!(parse::program(program).unwrap().unwrap(), Program {
+            prompts: vec![
+                Prompt {
+                    is_main: true,
+                    name: "bob".into(),
+                    options: PromptOptions::default(),
+                    header: String::new(),
+                    comment: Some(Comment("# Saved on Windows".into())),
+                    statements: vec![]
+                },
+            ],
+            comment: None
+        });
+    }
+
+    #[test]
+    fn parse_program_with_top_level_statements() {
+        let program = r#"
+            # Quick one liner
+            $AI -> bob
+            bob {
+            }
+        "#;
+
+        assert_eq!(parse::program(program).unwrap().unwrap(), Program {
+            prompts: vec![
+                Prompt {
+                    is_main: true,
+                    name: IMPLICIT_MAIN_PROMPT.into(),
+                    options: PromptOptions::default(),
+                    header: String::new(),
+                    comment: None,
+                    statements: vec![
+                        Statement::Comment(Comment("# Quick one liner".into())),
+                        Statement::PipeStatement(PipeStatement {
+                            call: PromptCall {
+                                names: vec![ String::from("bob") ]
+                            },
+                            subject: PipeSubject::Variable(Variable(String::from("AI")))
+                        })
+                    ]
+                },
+                Prompt {
+                    is_main: false,
+                    name: "bob".into(),
+                    options: PromptOptions::default(),
+                    header: String::new(),
+                    comment: None,
+                    statements: vec![]
+                },
+            ],
+            comment: None
         });
     }
 
+    #[test]
+    fn parse_program_with_top_level_statements_and_main_prompt() {
+        let program = "$AI -> main\nmain {\n}\n";
+
+        assert!(parse::program(program).is_err());
+    }
+
+    #[test]
+    fn merge_programs() {
+        let mut program = parse::program("bob {\n}\n").unwrap().unwrap();
+
+        program.merge(parse::program("alice {\n}\n").unwrap().unwrap()).unwrap();
+        assert_eq!(
+            program.prompts.iter().map(|p| (p.name.as_str(), p.is_main)).collect::<Vec<_>>(),
+            vec![("bob", true), ("alice", false)]
+        );
+
+        assert_eq!(
+            program.merge(parse::program("alice {\n}\n").unwrap().unwrap()),
+            Err(String::from("alice"))
+        );
+    }
 
     #[test]
     fn parse_match_statement_with_no_actions() {
         let match_statement = "match $variable {}";
 
         assert_eq!(parse::match_statement(match_statement).unwrap(), MatchStatement {
-            variable: Variable(String::from("variable")),
+            subject: PipeSubject::Variable(Variable(String::from("variable"))),
             cases: vec![]
         });
     }
@@ -180,7 +360,7 @@ mod tests {
         }";
 
         assert_eq!(parse::match_statement(match_statement).unwrap(), MatchStatement {
-            variable: Variable(String::from("variable")),
+            subject: PipeSubject::Variable(Variable(String::from("variable"))),
             cases: vec![
                 MatchCase {
                     regex: Regex::new("(?i:^yes)").unwrap(),
@@ -196,6 +376,23 @@ mod tests {
         });
     }
 
+    #[test]
+    fn parse_match_statement_with_command() {
+        let match_statement = "match `git status --porcelain` {
+            (.+) => `git stash`
+        }";
+
+        assert_eq!(parse::match_statement(match_statement).unwrap(), MatchStatement {
+            subject: PipeSubject::Command(Command(String::from("git status --porcelain"))),
+            cases: vec![
+                MatchCase {
+                    regex: Regex::new("(.+)").unwrap(),
+                    action: MatchAction::Command(Command(String::from("git stash")))
+                },
+            ]
+        });
+    }
+
     #[test]
     fn parse_regex() {
         assert_eq!(
@@ -257,14 +454,21 @@ mod tests {
                 direction: None,
                 eager: None,
                 history: Some(false),
+                max_words: None,
+                stop: None,
+                tokens_balance: None,
                 description: Some(
                     "Answer this question with a yes or no answer. Is this input valid JSON \
                     that can be used with NodeJS's console.table method cleanly?".into()
                 )
             },
+            header: "history: false\ndescription: \"Answer this question with a yes or no answer. \
+                Is this input valid JSON that can be used with NodeJS's console.table method \
+                cleanly?\"".into(),
+            comment: None,
             statements: vec![
                 Statement::MatchStatement(MatchStatement {
-                    variable: Variable(String::from("AI")),
+                    subject: PipeSubject::Variable(Variable(String::from("AI"))),
                     cases: vec![]
                 })
             ]
@@ -288,12 +492,125 @@ mod tests {
                 direction: Some("Can you summerize the contents of this HTML page?".into()),
                 ..PromptOptions::default()
             },
+            header: "direction: \"Can you summerize the contents of this HTML page?\"".into(),
+            comment: None,
             statements: vec![
                 Statement::Command(Command("echo $AI".into()))
             ]
         });
     }
 
+    #[test]
+    fn parse_prompt_with_stop_sequences() {
+        let input = r#"
+            mom
+                direction: Write only the next line from the mom.
+                stop:
+                    - "dad:"
+                    - "toddler_one:"
+            {
+                $AI -> dad
+            }
+        "#;
+
+        assert_eq!(parse::prompt(input).unwrap().unwrap().options, PromptOptions {
+            direction: Some("Write only the next line from the mom.".into()),
+            stop: Some(vec![ String::from("dad:"), String::from("toddler_one:") ]),
+            ..PromptOptions::default()
+        });
+    }
+
+    #[test]
+    fn parse_prompt_with_too_many_stop_sequences() {
+        let input = r#"
+            mom
+                stop: ["a", "b, c", "d"]
+            {
+            }
+        "#;
+
+        assert!(parse::prompt(input).unwrap().is_ok());
+
+        let input = r#"
+            mom
+                stop: ["a", "b, c", "d", "e"]
+            {
+            }
+        "#;
+
+        let error = parse::prompt(input).unwrap().unwrap_err().to_string();
+        assert!(error.contains("prompt mom has 5 stop sequences"), "{error}");
+    }
+
+    #[test]
+    fn parse_prompt_with_tab_indented_options() {
+        let input = "
+\tgreeter
+\t\tdirection: Say hëllo
+\t\tstop:
+\t\t  - \"ünd\"
+\t{
+\t}
+";
+
+        assert_eq!(parse::prompt(input).unwrap().unwrap().options, PromptOptions {
+            direction: Some("Say hëllo".into()),
+            stop: Some(vec![ String::from("ünd") ]),
+            ..PromptOptions::default()
+        });
+    }
+
+    #[test]
+    fn dedent_lines_with_less_indentation() {
+        assert_eq!(dedent("\n    a: 1\n  b\n\tc\n"), "\na: 1\nb\nc\n");
+        assert_eq!(dedent("\n  é: 1\n é\n"), "\né: 1\né\n");
+    }
+
+    #[test]
+    fn parse_print_statement() {
+        assert_eq!(
+            parse::print_statement(r#"print "The AI said \"$AI\"""#).unwrap(),
+            Print(String::from(r#"The AI said "$AI""#))
+        );
+
+        assert_eq!(
+            parse::print_statement(r#"print "back\\slash""#).unwrap(),
+            Print(String::from(r"back\\slash"))
+        );
+
+        assert_eq!(
+            parse::print_statement(r#"print "Price: \$5, \\$AI""#).unwrap(),
+            Print(String::from(r"Price: \$5, \\$AI"))
+        );
+
+        assert!(parse::print_statement("print `echo $AI`").is_err());
+    }
+
+    #[test]
+    fn parse_if_statement() {
+        let input = r#"
+            if `test -n "$AI"` {
+                print "$AI"
+                $AI -> bar
+            }
+        "#;
+
+        assert_eq!(parse::statements(input).unwrap(), vec![
+            Statement::IfStatement(IfStatement {
+                condition: Command(String::from(r#"test -n "$AI""#)),
+                statements: vec![
+                    Statement::Print(Print(String::from("$AI"))),
+                    Statement::PipeStatement(PipeStatement {
+                        call: PromptCall {
+                            names: vec![ String::from("bar") ]
+                        },
+                        subject: PipeSubject::Variable(Variable(String::from("AI")))
+                    }),
+                ]
+            })
+        ]);
+    }
+
     #[test]
     fn parse_pipe_statement_with_variable_subject() {
         let pipe_statement = "$LINE -> foo, bar";
@@ -325,6 +642,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_pipe_statement_with_stderr() {
+        let pipe_statement = "`cargo build` 2> -> fixer";
+        assert_eq!(
+            parse::pipe_statement(pipe_statement).unwrap(),
+            PipeStatement {
+                subject: PipeSubject::Stderr(Command(String::from("cargo build"))),
+                call: PromptCall {
+                    names: vec![ String::from("fixer") ]
+                }
+            }
+        );
+    }
+
     #[test]
     fn parse_multiple_different_statement() {
         let input = r#"
@@ -338,7 +669,7 @@ mod tests {
 
         assert_eq!(parse::statements(input).unwrap(), vec![
             Statement::MatchStatement(MatchStatement {
-                variable: Variable(String::from("variable")),
+                subject: PipeSubject::Variable(Variable(String::from("variable"))),
                 cases: vec![
                     MatchCase {
                         regex: Regex::new("(?i:yes)").unwrap(),
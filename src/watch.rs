@@ -1,28 +1,29 @@
 use std::fs::{OpenOptions};
-use std::io::{self,BufRead,BufReader,Write};
+use std::io::{BufRead,BufReader};
 use std::path::PathBuf;
 use tokio::sync::mpsc;
 use notify::{Watcher, RecursiveMode, event::{Event,EventKind}};
+use super::output::write_stdout;
 
-pub async fn monitor(path: PathBuf) -> notify::Result<()> {
+/// Prints the transcript of a session, or only its last `tail` lines, and then follows anything
+/// appended to it.
+pub async fn monitor(path: PathBuf, tail: Option<usize>) -> notify::Result<()> {
     let (tx, mut rx) = mpsc::channel(256);
     let content = std::fs::read_to_string(&path).unwrap_or_default();
     let transcript = {
         match content.find("<->") {
-            Some(divider_index) => content
-                .clone()
-                .split_off(divider_index + 4)
-                .trim_start()
-                .lines()
-                .map(|line| format!("{line}\n\n"))
-                .collect(),
+            Some(divider_index) => {
+                let transcript = content.clone().split_off(divider_index + 4);
+                let lines = transcript.trim_start().lines().collect::<Vec<_>>();
+                let skip = lines.len().saturating_sub(tail.unwrap_or(lines.len()));
+
+                lines[skip..].iter().map(|line| format!("{line}\n\n")).collect()
+            },
             None => String::new()
         }
     };
 
-    println!("");
-    print!("{transcript}");
-    io::stdout().flush().unwrap();
+    write_stdout(format!("\n{transcript}").as_bytes());
 
     let file_path = path.clone();
     let mut lines = content.lines().map(|s| s.to_string()).collect::<Vec<_>>();
@@ -49,7 +50,7 @@ pub async fn monitor(path: PathBuf) -> notify::Result<()> {
                     _ => (),
                 }
            },
-           Err(e) => println!("watch error: {:?}", e),
+           Err(e) => write_stdout(format!("watch error: {:?}\n", e).as_bytes()),
         }
     })?;
 
@@ -60,7 +61,7 @@ pub async fn monitor(path: PathBuf) -> notify::Result<()> {
     watcher.watch(&path, RecursiveMode::Recursive)?;
 
     while let Some(appended) = rx.recv().await {
-        println!("{}", appended);
+        write_stdout(format!("{appended}\n").as_bytes());
     }
 
     Ok(())